@@ -1,11 +1,26 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
 
 use arc_swap::ArcSwap;
+use bb8_rusqlite::RusqliteConnectionManager;
 use camino::Utf8PathBuf;
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use rangemap::{RangeInclusiveMap, RangeInclusiveSet};
-use tokio::{sync::mpsc::Sender, task::block_in_place};
-use tracing::warn;
+use rusqlite::Connection;
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    task::block_in_place,
+    time::sleep,
+};
+use tracing::{error, warn};
 
 use crate::{
     actor::ActorId,
@@ -23,8 +38,7 @@ pub struct Agent(pub Arc<AgentInner>);
 
 pub struct AgentInner {
     pub actor_id: ActorId,
-    pub ro_pool: SqlitePool,
-    pub rw_pool: SqlitePool,
+    pub pool: SplitPool,
     pub config: ArcSwap<Config>,
     pub gossip_addr: SocketAddr,
     pub api_addr: SocketAddr,
@@ -33,17 +47,22 @@ pub struct AgentInner {
     pub bookie: Bookie,
     pub subscribers: Subscribers,
     pub tx_bcast: Sender<BroadcastInput>,
+    // drained by process_apply_queue, which performs the deferred SQLite writes
+    pub tx_apply: Sender<(ActorId, i64)>,
     pub schema: RwLock<NormalizedSchema>,
 }
 
 impl Agent {
-    /// Return a borrowed [SqlitePool]
+    pub fn pool(&self) -> &SplitPool {
+        &self.0.pool
+    }
+
     pub fn read_only_pool(&self) -> &SqlitePool {
-        &self.0.ro_pool
+        self.0.pool.read_pool()
     }
 
-    pub fn read_write_pool(&self) -> &SqlitePool {
-        &self.0.rw_pool
+    pub fn tx_apply(&self) -> &Sender<(ActorId, i64)> {
+        &self.0.tx_apply
     }
 
     pub fn actor_id(&self) -> ActorId {
@@ -85,6 +104,137 @@ impl Agent {
     }
 }
 
+pub type SqlitePooledConn<'a> = bb8::PooledConnection<'a, RusqliteConnectionManager>;
+type PoolError = bb8::RunError<bb8_rusqlite::Error>;
+
+// writes go through a single SQLite connection guarded by a semaphore; reads
+// go straight to the read pool
+#[derive(Clone)]
+pub struct SplitPool(Arc<SplitPoolInner>);
+
+// normal writers may only take a permit while more than one remains; the
+// last permit is reserved for write_priority
+const WRITE_PERMITS: usize = 2;
+
+struct SplitPoolInner {
+    read: SqlitePool,
+    // a single shared semaphore gating the size-1 writer pool; the last permit
+    // is reserved for priority writes. The semaphore is fair (FIFO): a
+    // write_normal already waiting on it holds the free permit while it waits,
+    // so a priority write that arrives after it queues behind it
+    write: SqlitePool,
+    write_sema: Arc<Semaphore>,
+}
+
+// releases its semaphore permit on drop
+pub struct WriteConn<'a> {
+    _permit: OwnedSemaphorePermit,
+    conn: SqlitePooledConn<'a>,
+}
+
+impl<'a> Deref for WriteConn<'a> {
+    type Target = SqlitePooledConn<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl<'a> DerefMut for WriteConn<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl SplitPool {
+    pub fn new(read: SqlitePool, write: SqlitePool) -> Self {
+        Self(Arc::new(SplitPoolInner {
+            read,
+            write,
+            write_sema: Arc::new(Semaphore::new(WRITE_PERMITS)),
+        }))
+    }
+
+    pub fn read_pool(&self) -> &SqlitePool {
+        &self.0.read
+    }
+
+    pub async fn read(&self) -> Result<SqlitePooledConn<'_>, PoolError> {
+        self.0.read.get().await
+    }
+
+    pub async fn write_normal(&self) -> Result<WriteConn<'_>, PoolError> {
+        // acquire 2 permits but hand one straight back, so a normal write can
+        // only proceed while >1 permit is free and, once it proceeds, leaves
+        // the reserved one free.
+        let mut permit = self
+            .0
+            .write_sema
+            .clone()
+            .acquire_many_owned(WRITE_PERMITS as u32)
+            .await
+            .expect("write semaphore is never closed");
+        // release all but one permit, keeping a single permit for this write
+        drop(permit.split(WRITE_PERMITS - 1));
+        Ok(WriteConn {
+            _permit: permit,
+            conn: self.0.write.get().await?,
+        })
+    }
+
+    // goes through the reserved permit, so foreground writes need not wait for
+    // the apply queue to drain; they still wait behind any write_normal already
+    // queued on the semaphore
+    pub async fn write_priority(&self) -> Result<WriteConn<'_>, PoolError> {
+        let permit = self
+            .0
+            .write_sema
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("write semaphore is never closed");
+        Ok(WriteConn {
+            _permit: permit,
+            conn: self.0.write.get().await?,
+        })
+    }
+}
+
+// a failed write_normal must not drop (actor_id, version): the caller already
+// booked it (see sync::apply_recovered_changeset), so losing it here would
+// leave a gap that generate_sync can never again detect
+const APPLY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// drains the queue fed by AgentInner::tx_apply, performing the deferred
+// SQLite writes through write_normal so the UDP/foca dispatch loop is never
+// blocked on write latency. `apply` is the repo's change-application
+// routine, run on a blocking thread against the writer connection.
+pub async fn process_apply_queue<F>(
+    pool: SplitPool,
+    mut rx: Receiver<(ActorId, i64)>,
+    tx: Sender<(ActorId, i64)>,
+    mut apply: F,
+) where
+    F: FnMut(&mut Connection, ActorId, i64),
+{
+    while let Some((actor_id, version)) = rx.recv().await {
+        let mut conn = match pool.write_normal().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(
+                    "could not acquire writer to apply ({actor_id}, {version}): {e}, requeueing"
+                );
+                sleep(APPLY_RETRY_DELAY).await;
+                if let Err(e) = tx.send((actor_id, version)).await {
+                    error!("could not requeue ({actor_id}, {version}) after a failed apply: {e}");
+                }
+                continue;
+            }
+        };
+        block_in_place(|| apply(&mut conn, actor_id, version));
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ReloadError {
     #[error(transparent)]
@@ -115,7 +265,7 @@ pub async fn reload(agent: &Agent, new_conf: Config) -> Result<(), ReloadError>
         warn!("reloaded ineffectual change: log_format");
     }
 
-    let mut conn = agent.read_write_pool().get().await?;
+    let mut conn = agent.pool().write_priority().await?;
     let mut schema_write = agent.0.schema.write();
 
     let new_schema =
@@ -260,3 +410,50 @@ impl Bookie {
         self.0.read()
     }
 }
+
+// SplitPool::write_normal/write_priority can't be exercised directly here:
+// they go through crate::sqlite::SqlitePool, which this snapshot doesn't
+// have a definition for. These tests cover the reservation trick itself
+// (acquire WRITE_PERMITS, hand back all but one) against a bare Semaphore,
+// which is the part that actually enforces normal writes never starve
+// write_priority.
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Semaphore;
+
+    use super::WRITE_PERMITS;
+
+    async fn acquire_normal(sema: &Arc<Semaphore>) -> tokio::sync::OwnedSemaphorePermit {
+        let mut permit = sema
+            .clone()
+            .acquire_many_owned(WRITE_PERMITS as u32)
+            .await
+            .unwrap();
+        drop(permit.split(WRITE_PERMITS - 1));
+        permit
+    }
+
+    #[tokio::test]
+    async fn normal_write_never_takes_the_reserved_permit() {
+        let sema = Arc::new(Semaphore::new(WRITE_PERMITS));
+        let _normal = acquire_normal(&sema).await;
+
+        // the reserved permit is still free, so a priority acquire succeeds
+        // immediately even while a normal write is in flight
+        let priority = sema.clone().try_acquire_owned();
+        assert!(priority.is_ok());
+    }
+
+    #[tokio::test]
+    async fn two_normal_writes_cannot_run_concurrently() {
+        let sema = Arc::new(Semaphore::new(WRITE_PERMITS));
+        let _first = acquire_normal(&sema).await;
+
+        // only the reserved permit remains, so a second normal acquire can't
+        // get the >1-permits-free it needs and must wait
+        let second = sema.clone().try_acquire_many_owned(WRITE_PERMITS as u32);
+        assert!(second.is_err());
+    }
+}
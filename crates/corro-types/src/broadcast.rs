@@ -1,9 +1,14 @@
 use std::{io, net::SocketAddr, num::NonZeroU32, time::Duration};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
 use foca::{Identity, Member, Notification, Runtime, Timer};
 
 use metrics::increment_counter;
+use rand::{rngs::OsRng, RngCore};
 use speedy::{Readable, Writable};
 use tokio::sync::mpsc::Sender;
 use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
@@ -14,13 +19,27 @@ use crate::{
     change::Change,
 };
 
+pub const AEAD_NONCE_LEN: usize = 24; // XChaCha20-Poly1305 nonce
+pub const AEAD_TAG_LEN: usize = 16; // Poly1305 tag
+
 pub const FRAGMENTS_AT: usize = 1420 // wg0 MTU
                               - 40 // 40 bytes IPv6 header
                               - 8; // UDP header bytes
-pub const EFFECTIVE_CAP: usize = FRAGMENTS_AT - 1; // fragmentation cap - 1 for the message type byte
+pub const EFFECTIVE_CAP: usize = FRAGMENTS_AT
+                               - 1 // message type byte
+                               - AEAD_NONCE_LEN // sealed frame nonce
+                               - AEAD_TAG_LEN; // sealed frame tag
 pub const HTTP_BROADCAST_SIZE: usize = 64 * 1024;
 pub const EFFECTIVE_HTTP_BROADCAST_SIZE: usize = HTTP_BROADCAST_SIZE - 1;
 
+// pre-shared cluster secret; when set, frames are sealed with
+// XChaCha20-Poly1305 instead of the plaintext CRC32 scheme
+pub type ClusterKey = [u8; 32];
+
+// leading byte of a frame, identifying how its payload is protected
+const FRAME_PLAINTEXT: u8 = 0;
+const FRAME_SEALED: u8 = 1;
+
 #[derive(Debug)]
 pub enum BroadcastSrc {
     Http(SocketAddr),
@@ -57,6 +76,8 @@ pub enum MessageEncodeError {
     Encode(#[from] speedy::Error),
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error("could not seal message")]
+    Seal,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -65,6 +86,14 @@ pub enum MessageDecodeError {
     Decode(#[from] speedy::Error),
     #[error("corrupted message, crc mismatch (got: {0}, expected {1})")]
     Corrupted(u32, u32),
+    #[error("authentication failed, message tag mismatch")]
+    Unauthenticated,
+    #[error("unexpected frame tag: {0}")]
+    UnknownFrame(u8),
+    #[error("frame too short to unseal")]
+    Truncated,
+    #[error("cluster key required to decode a sealed frame")]
+    MissingKey,
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -74,41 +103,97 @@ impl Message {
         Self::read_from_buffer(slice.as_ref())
     }
 
-    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), MessageEncodeError> {
+    pub fn encode(
+        &self,
+        key: Option<&ClusterKey>,
+        buf: &mut BytesMut,
+    ) -> Result<(), MessageEncodeError> {
         self.write_to_stream(buf.writer())?;
-        let mut bytes = buf.split();
-        let hash = crc32fast::hash(&bytes);
-        bytes.put_u32(hash);
+        let plaintext = buf.split();
+
+        let mut frame = BytesMut::new();
+        match key {
+            Some(key) => {
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let mut nonce = [0u8; AEAD_NONCE_LEN];
+                OsRng.fill_bytes(&mut nonce);
+                let sealed = cipher
+                    .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+                    .map_err(|_| MessageEncodeError::Seal)?;
+
+                // nonce || ciphertext || tag, the Poly1305 tag replaces the CRC
+                frame.put_u8(FRAME_SEALED);
+                frame.put_slice(&nonce);
+                frame.put_slice(&sealed);
+            }
+            None => {
+                let hash = crc32fast::hash(&plaintext);
+                frame.put_u8(FRAME_PLAINTEXT);
+                frame.put_slice(&plaintext);
+                frame.put_u32(hash);
+            }
+        }
 
         let mut codec = LengthDelimitedCodec::builder()
             .length_field_type::<u32>()
             .new_codec();
-        codec.encode(bytes.split().freeze(), buf)?;
+        codec.encode(frame.freeze(), buf)?;
 
         Ok(())
     }
 
-    pub fn from_buf(buf: &mut BytesMut) -> Result<Message, MessageDecodeError> {
+    pub fn from_buf(
+        buf: &mut BytesMut,
+        key: Option<&ClusterKey>,
+    ) -> Result<Message, MessageDecodeError> {
         let len = buf.len();
         trace!("successfully decoded a frame, len: {len}");
 
-        let mut crc_bytes = buf.split_off(len - 4);
-
-        let crc = crc_bytes.get_u32();
-        let new_crc = crc32fast::hash(&buf);
-        if crc != new_crc {
-            return Err(MessageDecodeError::Corrupted(crc, new_crc));
+        if buf.is_empty() {
+            return Err(MessageDecodeError::Truncated);
         }
 
-        Ok(Message::from_slice(&buf)?)
+        match buf.get_u8() {
+            FRAME_PLAINTEXT => {
+                let len = buf.len();
+                if len < 4 {
+                    return Err(MessageDecodeError::Truncated);
+                }
+                let mut crc_bytes = buf.split_off(len - 4);
+
+                let crc = crc_bytes.get_u32();
+                let new_crc = crc32fast::hash(buf);
+                if crc != new_crc {
+                    return Err(MessageDecodeError::Corrupted(crc, new_crc));
+                }
+
+                Ok(Message::from_slice(buf)?)
+            }
+            FRAME_SEALED => {
+                let key = key.ok_or(MessageDecodeError::MissingKey)?;
+                if buf.len() < AEAD_NONCE_LEN + AEAD_TAG_LEN {
+                    return Err(MessageDecodeError::Truncated);
+                }
+
+                let nonce = buf.split_to(AEAD_NONCE_LEN);
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let plaintext = cipher
+                    .decrypt(XNonce::from_slice(&nonce), buf.as_ref())
+                    .map_err(|_| MessageDecodeError::Unauthenticated)?;
+
+                Ok(Message::from_slice(&plaintext)?)
+            }
+            tag => Err(MessageDecodeError::UnknownFrame(tag)),
+        }
     }
 
     pub fn decode(
         codec: &mut LengthDelimitedCodec,
         buf: &mut BytesMut,
+        key: Option<&ClusterKey>,
     ) -> Result<Option<Self>, MessageDecodeError> {
         Ok(match codec.decode(buf)? {
-            Some(mut buf) => Some(Self::from_buf(&mut buf)?),
+            Some(mut buf) => Some(Self::from_buf(&mut buf, key)?),
             None => None,
         })
     }
@@ -175,4 +260,109 @@ impl<T> DispatchRuntime<T> {
             active: false,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn sample_message() -> Message {
+        Message::V1(MessageV1::Change {
+            actor_id: ActorId(Uuid::from_u128(1)),
+            version: 1,
+            changeset: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn plaintext_round_trip() {
+        let msg = sample_message();
+        let mut buf = BytesMut::new();
+        msg.encode(None, &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let decoded = Message::decode(&mut codec, &mut buf, None).unwrap().unwrap();
+        assert!(matches!(decoded, Message::V1(MessageV1::Change { version: 1, .. })));
+    }
+
+    #[test]
+    fn sealed_round_trip() {
+        let key: ClusterKey = [7u8; 32];
+        let msg = sample_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let decoded = Message::decode(&mut codec, &mut buf, Some(&key))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(decoded, Message::V1(MessageV1::Change { version: 1, .. })));
+    }
+
+    #[test]
+    fn sealed_frame_rejects_wrong_key() {
+        let key: ClusterKey = [7u8; 32];
+        let wrong_key: ClusterKey = [9u8; 32];
+        let msg = sample_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let err = Message::from_buf(&mut frame, Some(&wrong_key)).unwrap_err();
+        assert!(matches!(err, MessageDecodeError::Unauthenticated));
+    }
+
+    #[test]
+    fn sealed_frame_without_key_is_rejected() {
+        let key: ClusterKey = [7u8; 32];
+        let msg = sample_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let err = Message::from_buf(&mut frame, None).unwrap_err();
+        assert!(matches!(err, MessageDecodeError::MissingKey));
+    }
+
+    #[test]
+    fn plaintext_frame_rejects_corrupted_bytes() {
+        let msg = sample_message();
+        let mut buf = BytesMut::new();
+        msg.encode(None, &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        // flip a byte in the plaintext payload, past the leading frame tag
+        frame[1] ^= 0xff;
+        let err = Message::from_buf(&mut frame, None).unwrap_err();
+        assert!(matches!(err, MessageDecodeError::Corrupted(_, _)));
+    }
+
+    #[test]
+    fn truncated_frame_is_rejected() {
+        let mut empty = BytesMut::new();
+        assert!(matches!(
+            Message::from_buf(&mut empty, None),
+            Err(MessageDecodeError::Truncated)
+        ));
+
+        let mut short = BytesMut::from(&[FRAME_SEALED][..]);
+        assert!(matches!(
+            Message::from_buf(&mut short, Some(&[0u8; 32])),
+            Err(MessageDecodeError::Truncated)
+        ));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, num::NonZeroUsize};
+
+use bytes::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::{
+    broadcast::Message,
+    config::{ChunkConfig, ConfigError},
+};
+
+// blake3 digest; CRC32 isn't collision-resistant enough to address content by hash
+pub type ChunkId = [u8; 32];
+
+// gear values for the rolling fingerprint, derived at compile time from a
+// splitmix64 sequence so the table is stable across builds
+const GEAR: [u64; 256] = build_gear();
+
+const fn build_gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        table[i] = z;
+        i += 1;
+    }
+    table
+}
+
+// gear-hash rolling fingerprint cut point, honoring min/avg/max from cfg
+fn cut_point(data: &[u8], cfg: &ChunkConfig) -> usize {
+    let len = data.len();
+    if len <= cfg.min {
+        return len;
+    }
+
+    let mask = cfg.mask();
+    let max = len.min(cfg.max);
+    let mut fp: u64 = 0;
+    for i in 0..max {
+        fp = (fp << 1).wrapping_add(GEAR[data[i] as usize]);
+        if i + 1 >= cfg.min && fp & mask == 0 {
+            return i + 1;
+        }
+    }
+    // force a boundary at the max size even if the fingerprint never matched
+    max
+}
+
+pub fn split_chunks<'a>(data: &'a [u8], cfg: &ChunkConfig) -> Vec<&'a [u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let cut = cut_point(rest, cfg);
+        let (chunk, tail) = rest.split_at(cut);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkedBuf {
+    pub ids: Vec<ChunkId>,
+}
+
+impl ChunkedBuf {
+    pub fn missing(&self, have: impl Fn(&ChunkId) -> bool) -> Vec<ChunkId> {
+        let mut seen = std::collections::HashSet::new();
+        self.ids
+            .iter()
+            .filter(|id| seen.insert(**id) && !have(id))
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChunkError {
+    #[error("missing chunk while reassembling: {0:?}")]
+    Missing(ChunkId),
+    #[error(transparent)]
+    Decode(#[from] speedy::Error),
+}
+
+pub struct ChunkStore {
+    cache: Mutex<LruCache<ChunkId, Bytes>>,
+    config: ChunkConfig,
+}
+
+impl ChunkStore {
+    pub fn new(capacity: NonZeroUsize, config: ChunkConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            cache: Mutex::new(LruCache::new(capacity)),
+            config,
+        })
+    }
+
+    // changesets serialized past this size go out as a ChunkManifest instead
+    // of inline, so the peer can resume/dedupe instead of refetching the lot
+    pub fn chunk_threshold(&self) -> usize {
+        self.config.max
+    }
+
+    pub fn contains(&self, id: &ChunkId) -> bool {
+        self.cache.lock().contains(id)
+    }
+
+    pub fn get(&self, id: &ChunkId) -> Option<Bytes> {
+        self.cache.lock().get(id).cloned()
+    }
+
+    pub fn put(&self, bytes: Bytes) -> ChunkId {
+        let id = *blake3::hash(&bytes).as_bytes();
+        self.cache.lock().put(id, bytes);
+        id
+    }
+
+    pub fn ingest(&self, data: &[u8]) -> ChunkedBuf {
+        if data.len() <= self.config.min {
+            return ChunkedBuf {
+                ids: if data.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![self.put(Bytes::copy_from_slice(data))]
+                },
+            };
+        }
+
+        let ids = split_chunks(data, &self.config)
+            .into_iter()
+            .map(|chunk| self.put(Bytes::copy_from_slice(chunk)))
+            .collect();
+        ChunkedBuf { ids }
+    }
+
+    // skips ids that have since been evicted
+    pub fn gather(&self, ids: &[ChunkId]) -> Vec<(ChunkId, Bytes)> {
+        let mut cache = self.cache.lock();
+        ids.iter()
+            .filter_map(|id| cache.get(id).map(|bytes| (*id, bytes.clone())))
+            .collect()
+    }
+
+    // draws from `received` first, falling back to chunks already held locally
+    pub fn reassemble(
+        &self,
+        ids: &[ChunkId],
+        received: &HashMap<ChunkId, Bytes>,
+    ) -> Result<Message, ChunkError> {
+        let mut buf = Vec::new();
+        for id in ids {
+            let bytes = received
+                .get(id)
+                .cloned()
+                .or_else(|| self.get(id))
+                .ok_or(ChunkError::Missing(*id))?;
+            buf.extend_from_slice(&bytes);
+        }
+        Ok(Message::from_slice(&buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(min: usize, avg: usize, max: usize) -> ChunkConfig {
+        ChunkConfig { min, avg, max }
+    }
+
+    #[test]
+    fn split_chunks_empty_buffer_yields_no_chunks() {
+        let chunks = split_chunks(&[], &ChunkConfig::default());
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn split_chunks_never_yields_a_chunk_below_min() {
+        let cfg = cfg(64, 256, 1024);
+        let data = vec![0u8; 2000];
+        for chunk in split_chunks(&data, &cfg) {
+            assert!(chunk.len() >= cfg.min || chunk.len() == data.len());
+        }
+    }
+
+    #[test]
+    fn split_chunks_forces_a_boundary_at_max_when_no_cut_point_matches() {
+        // a mask that can never be satisfied by the gear fingerprint forces
+        // every chunk but the trailing remainder to be cut at exactly `max`.
+        let cfg = ChunkConfig {
+            min: 4,
+            avg: 1 << 63,
+            max: 32,
+        };
+        let data = vec![0u8; 100];
+        let chunks = split_chunks(&data, &cfg);
+        assert!(chunks.iter().take(chunks.len() - 1).all(|c| c.len() == cfg.max));
+        assert!(chunks.iter().map(|c| c.len()).sum::<usize>() == data.len());
+    }
+
+    #[test]
+    fn split_chunks_reassembles_to_the_original_bytes() {
+        let cfg = cfg(16, 64, 256);
+        let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+        let reassembled: Vec<u8> = split_chunks(&data, &cfg).concat();
+        assert_eq!(reassembled, data);
+    }
+
+    #[test]
+    fn chunk_store_ingest_below_min_is_a_single_chunk() {
+        let store = ChunkStore::new(NonZeroUsize::new(16).unwrap(), cfg(64, 256, 1024)).unwrap();
+        let chunked = store.ingest(b"small");
+        assert_eq!(chunked.ids.len(), 1);
+    }
+
+    #[test]
+    fn chunk_store_ingest_empty_is_no_chunks() {
+        let store =
+            ChunkStore::new(NonZeroUsize::new(16).unwrap(), ChunkConfig::default()).unwrap();
+        let chunked = store.ingest(&[]);
+        assert!(chunked.ids.is_empty());
+    }
+
+    #[test]
+    fn chunk_store_new_rejects_an_invalid_config() {
+        let bad = cfg(64, 0, 1024);
+        assert!(ChunkStore::new(NonZeroUsize::new(16).unwrap(), bad).is_err());
+    }
+
+    #[test]
+    fn chunked_buf_missing_dedupes_and_filters_held_ids() {
+        let held: ChunkId = [1; 32];
+        let missing_id: ChunkId = [2; 32];
+        let buf = ChunkedBuf {
+            ids: vec![held, missing_id, missing_id],
+        };
+        let missing = buf.missing(|id| *id == held);
+        assert_eq!(missing, vec![missing_id]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::net::SocketAddr;
+
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::broadcast::ClusterKey;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Plaintext,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GossipConfig {
+    // hex-encoded 32-byte pre-shared cluster secret; when set, frames are
+    // sealed with XChaCha20-Poly1305 instead of the plaintext CRC32 scheme
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+}
+
+// `avg` must be a power of two: it picks the number of fingerprint bits
+// ChunkConfig::mask() masks on (cut probability is 1 / avg)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkConfig {
+    #[serde(default = "default_min_chunk_size")]
+    pub min: usize,
+    #[serde(default = "default_avg_chunk_size")]
+    pub avg: usize,
+    #[serde(default = "default_max_chunk_size")]
+    pub max: usize,
+}
+
+fn default_min_chunk_size() -> usize {
+    2 * 1024
+}
+fn default_avg_chunk_size() -> usize {
+    8 * 1024
+}
+fn default_max_chunk_size() -> usize {
+    64 * 1024
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            min: default_min_chunk_size(),
+            avg: default_avg_chunk_size(),
+            max: default_max_chunk_size(),
+        }
+    }
+}
+
+impl ChunkConfig {
+    pub fn mask(&self) -> u64 {
+        self.avg as u64 - 1
+    }
+
+    // enforced by ChunkStore::new and Config::validate_chunking, so a bad
+    // `avg` never reaches mask() and panics on the subtract-overflow above
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.avg == 0 {
+            return Err(ConfigError::ChunkAvgZero);
+        }
+        if !self.avg.is_power_of_two() {
+            return Err(ConfigError::ChunkAvgNotPowerOfTwo(self.avg));
+        }
+        if self.min > self.avg {
+            return Err(ConfigError::ChunkMinAboveAvg {
+                min: self.min,
+                avg: self.avg,
+            });
+        }
+        if self.avg > self.max {
+            return Err(ConfigError::ChunkAvgAboveMax {
+                avg: self.avg,
+                max: self.max,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub db_path: Utf8PathBuf,
+    pub gossip_addr: SocketAddr,
+    pub api_addr: SocketAddr,
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    #[serde(default)]
+    pub bootstrap: Vec<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub schema_paths: Vec<Utf8PathBuf>,
+    #[serde(default)]
+    pub gossip: GossipConfig,
+    #[serde(default)]
+    pub chunking: ChunkConfig,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cluster encryption key is not valid hex: {0}")]
+    InvalidKey(#[from] hex::FromHexError),
+    #[error("cluster encryption key must be 32 bytes, got {0}")]
+    KeyLength(usize),
+    #[error("chunking.avg must be non-zero, got 0")]
+    ChunkAvgZero,
+    #[error("chunking.avg must be a power of two, got {0}")]
+    ChunkAvgNotPowerOfTwo(usize),
+    #[error("chunking.min ({min}) must be <= chunking.avg ({avg})")]
+    ChunkMinAboveAvg { min: usize, avg: usize },
+    #[error("chunking.avg ({avg}) must be <= chunking.max ({max})")]
+    ChunkAvgAboveMax { avg: usize, max: usize },
+}
+
+impl Config {
+    pub fn cluster_key(&self) -> Result<Option<ClusterKey>, ConfigError> {
+        let hex_key = match &self.gossip.encryption_key {
+            Some(hex_key) => hex_key,
+            None => return Ok(None),
+        };
+        let bytes = hex::decode(hex_key)?;
+        let key: ClusterKey = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| ConfigError::KeyLength(bytes.len()))?;
+        Ok(Some(key))
+    }
+
+    pub fn validate_chunking(&self) -> Result<(), ConfigError> {
+        self.chunking.validate()
+    }
+}
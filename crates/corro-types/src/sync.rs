@@ -0,0 +1,676 @@
+use std::{
+    collections::HashMap,
+    io,
+    ops::RangeInclusive,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use speedy::{Readable, Writable};
+use tokio::sync::mpsc::{error::SendError, Sender};
+use tokio_util::codec::{Encoder, LengthDelimitedCodec};
+use tracing::trace;
+
+use crate::{
+    actor::ActorId,
+    agent::{Bookie, KnownDbVersion},
+    broadcast::{ClusterKey, Message, MessageV1, AEAD_NONCE_LEN, AEAD_TAG_LEN},
+    change::Change,
+    chunker::{ChunkError, ChunkId, ChunkStore, ChunkedBuf},
+};
+
+// same framing as crate::broadcast::Message, so one cluster key protects both
+const FRAME_PLAINTEXT: u8 = 0;
+const FRAME_SEALED: u8 = 1;
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub enum SyncMessage {
+    V1(SyncMessageV1),
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub enum SyncMessageV1 {
+    State(SyncStateV1),
+    Changeset {
+        actor_id: ActorId,
+        version: i64,
+        changeset: Vec<Change>,
+    },
+    // advertises the content-addressed chunks of a large changeset so the
+    // peer can request only the ones it lacks
+    ChunkManifest {
+        actor_id: ActorId,
+        version: i64,
+        ids: Vec<ChunkId>,
+    },
+    ChunkRequest {
+        ids: Vec<ChunkId>,
+    },
+    Chunks {
+        chunks: Vec<Chunk>,
+    },
+}
+
+#[derive(Debug, Clone, Readable, Writable)]
+pub struct Chunk {
+    pub id: ChunkId,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Readable, Writable)]
+pub struct SyncStateV1 {
+    pub actor_id: ActorId,
+    pub heads: HashMap<ActorId, i64>,
+    pub need: HashMap<ActorId, Vec<RangeInclusive<i64>>>,
+    pub partial_need: HashMap<ActorId, HashMap<i64, Vec<RangeInclusive<i64>>>>,
+}
+
+impl SyncStateV1 {
+    pub fn needs(&self, actor_id: ActorId, version: i64) -> bool {
+        if let Some(needed) = self.need.get(&actor_id) {
+            if needed.iter().any(|range| range.contains(&version)) {
+                return true;
+            }
+        }
+        version > self.heads.get(&actor_id).copied().unwrap_or(0)
+    }
+}
+
+// Cleared versions are treated as satisfied, so compacted versions are never
+// re-requested
+pub fn generate_sync(bookie: &Bookie, actor_id: ActorId) -> SyncStateV1 {
+    let mut state = SyncStateV1 {
+        actor_id,
+        ..Default::default()
+    };
+
+    let booked = bookie.read();
+    for (actor_id, booked) in booked.iter() {
+        let last = match booked.last() {
+            Some(last) => last,
+            None => continue,
+        };
+        state.heads.insert(*actor_id, last);
+
+        let read = booked.read();
+
+        let gaps: Vec<RangeInclusive<i64>> = read.gaps(&(1..=last)).collect();
+        if !gaps.is_empty() {
+            state.need.insert(*actor_id, gaps);
+        }
+
+        for (range, known) in read.iter() {
+            if let KnownDbVersion::Partial { seqs, last_seq, .. } = known {
+                let missing: Vec<RangeInclusive<i64>> = seqs.gaps(&(0..=*last_seq)).collect();
+                if !missing.is_empty() {
+                    state
+                        .partial_need
+                        .entry(*actor_id)
+                        .or_default()
+                        .insert(*range.start(), missing);
+                }
+            }
+        }
+    }
+
+    state
+}
+
+// a whole version (seqs == None) or the missing seq ranges within a
+// partially-applied one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncNeed {
+    pub actor_id: ActorId,
+    pub version: i64,
+    pub seqs: Option<Vec<RangeInclusive<i64>>>,
+}
+
+// Cleared versions are skipped — they were advertised as satisfied
+pub fn changes_to_send(bookie: &Bookie, their: &SyncStateV1) -> Vec<SyncNeed> {
+    let mut needs = Vec::new();
+
+    let booked = bookie.read();
+    for (actor_id, booked) in booked.iter() {
+        let read = booked.read();
+        let partial = their.partial_need.get(actor_id);
+
+        for (range, known) in read.iter() {
+            if matches!(known, KnownDbVersion::Cleared) {
+                continue;
+            }
+
+            for version in *range.start()..=*range.end() {
+                if let Some(missing) = partial.and_then(|p| p.get(&version)) {
+                    // the peer has this version partially applied; stream only
+                    // the sequence ranges it is still missing
+                    needs.push(SyncNeed {
+                        actor_id: *actor_id,
+                        version,
+                        seqs: Some(missing.clone()),
+                    });
+                } else if their.needs(*actor_id, version) {
+                    needs.push(SyncNeed {
+                        actor_id: *actor_id,
+                        version,
+                        seqs: None,
+                    });
+                }
+            }
+        }
+    }
+
+    needs
+}
+
+// feeds a changeset recovered over sync through the same apply path as live
+// broadcasts: book it, then hand it to process_apply_queue via tx_apply
+pub async fn apply_recovered_changeset(
+    bookie: &Bookie,
+    tx_apply: &Sender<(ActorId, i64)>,
+    actor_id: ActorId,
+    version: i64,
+    known: KnownDbVersion,
+) -> Result<(), SendError<(ActorId, i64)>> {
+    bookie.add(actor_id, version, known);
+    tx_apply.send((actor_id, version)).await
+}
+
+// splits a serialized changeset into content-defined chunks held in `store`
+// and returns the manifest of ids to advertise
+pub fn advertise_chunks(
+    store: &ChunkStore,
+    actor_id: ActorId,
+    version: i64,
+    serialized: &[u8],
+) -> SyncMessageV1 {
+    let chunked = store.ingest(serialized);
+    SyncMessageV1::ChunkManifest {
+        actor_id,
+        version,
+        ids: chunked.ids,
+    }
+}
+
+// on a ChunkManifest, the ids we still lack and should request
+pub fn missing_chunks(store: &ChunkStore, ids: &[ChunkId]) -> Vec<ChunkId> {
+    ChunkedBuf { ids: ids.to_vec() }.missing(|id| store.contains(id))
+}
+
+// gathers the bytes for a peer's requested chunk ids, skipping evicted ones
+pub fn fulfill_chunk_request(store: &ChunkStore, ids: &[ChunkId]) -> SyncMessageV1 {
+    let chunks = store
+        .gather(ids)
+        .into_iter()
+        .map(|(id, data)| Chunk {
+            id,
+            data: data.to_vec(),
+        })
+        .collect();
+    SyncMessageV1::Chunks { chunks }
+}
+
+// reassembles the full changeset once every advertised chunk is available,
+// preferring freshly `received` bytes over ones the store already holds
+pub fn reassemble_changeset(
+    store: &ChunkStore,
+    ids: &[ChunkId],
+    received: Vec<Chunk>,
+) -> Result<Message, ChunkError> {
+    let received: HashMap<ChunkId, Bytes> = received
+        .into_iter()
+        .map(|chunk| (chunk.id, Bytes::from(chunk.data)))
+        .collect();
+    store.reassemble(ids, &received)
+}
+
+// serializes a changeset the same way crate::broadcast::Message does and, if
+// it's past store's chunk_threshold, chunks it instead of sending it inline
+pub fn prepare_changeset_frame(
+    store: &ChunkStore,
+    actor_id: ActorId,
+    version: i64,
+    changeset: Vec<Change>,
+) -> Result<SyncMessageV1, speedy::Error> {
+    let message = Message::V1(MessageV1::Change {
+        actor_id,
+        version,
+        changeset: changeset.clone(),
+    });
+    let mut buf = BytesMut::new();
+    message.write_to_stream(buf.writer())?;
+
+    if buf.len() > store.chunk_threshold() {
+        Ok(advertise_chunks(store, actor_id, version, &buf))
+    } else {
+        Ok(SyncMessageV1::Changeset {
+            actor_id,
+            version,
+            changeset,
+        })
+    }
+}
+
+// responder-side dispatch for the chunk sub-protocol: advertises what we're
+// missing on a manifest, or fulfills an incoming request. Anything else
+// (State, Changeset, Chunks) isn't a message this side needs to answer.
+pub fn respond_to_chunk_message(store: &ChunkStore, msg: &SyncMessageV1) -> Option<SyncMessageV1> {
+    match msg {
+        SyncMessageV1::ChunkManifest { ids, .. } => Some(SyncMessageV1::ChunkRequest {
+            ids: missing_chunks(store, ids),
+        }),
+        SyncMessageV1::ChunkRequest { ids } => Some(fulfill_chunk_request(store, ids)),
+        _ => None,
+    }
+}
+
+// requester-side counterpart to prepare_changeset_frame: turns a Chunks reply
+// into the decoded Message once every chunk it names is accounted for
+pub fn receive_chunks(
+    store: &ChunkStore,
+    ids: &[ChunkId],
+    chunks: Vec<Chunk>,
+) -> Result<Message, ChunkError> {
+    reassemble_changeset(store, ids, chunks)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyncMessageEncodeError {
+    #[error(transparent)]
+    Encode(#[from] speedy::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not seal sync message")]
+    Seal,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyncMessageDecodeError {
+    #[error(transparent)]
+    Decode(#[from] speedy::Error),
+    #[error("corrupted sync message, crc mismatch (got: {0}, expected {1})")]
+    Corrupted(u32, u32),
+    #[error("authentication failed, sync message tag mismatch")]
+    Unauthenticated,
+    #[error("unexpected sync frame tag: {0}")]
+    UnknownFrame(u8),
+    #[error("sync frame too short to decode")]
+    Truncated,
+    #[error("cluster key required to decode a sealed sync frame")]
+    MissingKey,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl SyncMessage {
+    pub fn from_slice<S: AsRef<[u8]>>(slice: S) -> Result<Self, speedy::Error> {
+        Self::read_from_buffer(slice.as_ref())
+    }
+
+    // same framing as crate::broadcast::Message::encode, sealed with the
+    // cluster key when one is set
+    pub fn encode(
+        &self,
+        key: Option<&ClusterKey>,
+        buf: &mut BytesMut,
+    ) -> Result<(), SyncMessageEncodeError> {
+        self.write_to_stream(buf.writer())?;
+        let plaintext = buf.split();
+
+        let mut frame = BytesMut::new();
+        match key {
+            Some(key) => {
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let mut nonce = [0u8; AEAD_NONCE_LEN];
+                OsRng.fill_bytes(&mut nonce);
+                let sealed = cipher
+                    .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+                    .map_err(|_| SyncMessageEncodeError::Seal)?;
+
+                // nonce || ciphertext || tag, the Poly1305 tag replaces the CRC
+                frame.put_u8(FRAME_SEALED);
+                frame.put_slice(&nonce);
+                frame.put_slice(&sealed);
+            }
+            None => {
+                let hash = crc32fast::hash(&plaintext);
+                frame.put_u8(FRAME_PLAINTEXT);
+                frame.put_slice(&plaintext);
+                frame.put_u32(hash);
+            }
+        }
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        codec.encode(frame.freeze(), buf)?;
+
+        Ok(())
+    }
+
+    pub fn from_buf(
+        buf: &mut BytesMut,
+        key: Option<&ClusterKey>,
+    ) -> Result<SyncMessage, SyncMessageDecodeError> {
+        let len = buf.len();
+        trace!("successfully decoded a sync frame, len: {len}");
+
+        if buf.is_empty() {
+            return Err(SyncMessageDecodeError::Truncated);
+        }
+
+        match buf.get_u8() {
+            FRAME_PLAINTEXT => {
+                let len = buf.len();
+                if len < 4 {
+                    return Err(SyncMessageDecodeError::Truncated);
+                }
+                let mut crc_bytes = buf.split_off(len - 4);
+
+                let crc = crc_bytes.get_u32();
+                let new_crc = crc32fast::hash(buf);
+                if crc != new_crc {
+                    return Err(SyncMessageDecodeError::Corrupted(crc, new_crc));
+                }
+
+                Ok(SyncMessage::from_slice(buf)?)
+            }
+            FRAME_SEALED => {
+                let key = key.ok_or(SyncMessageDecodeError::MissingKey)?;
+                if buf.len() < AEAD_NONCE_LEN + AEAD_TAG_LEN {
+                    return Err(SyncMessageDecodeError::Truncated);
+                }
+
+                let nonce = buf.split_to(AEAD_NONCE_LEN);
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let plaintext = cipher
+                    .decrypt(XNonce::from_slice(&nonce), buf.as_ref())
+                    .map_err(|_| SyncMessageDecodeError::Unauthenticated)?;
+
+                Ok(SyncMessage::from_slice(&plaintext)?)
+            }
+            tag => Err(SyncMessageDecodeError::UnknownFrame(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::Decoder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::agent::{Bookie, KnownDbVersion};
+
+    fn actor() -> ActorId {
+        ActorId(Uuid::from_u128(1))
+    }
+
+    fn sample_sync_message() -> SyncMessage {
+        SyncMessage::V1(SyncMessageV1::Changeset {
+            actor_id: actor(),
+            version: 1,
+            changeset: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn sync_message_plaintext_round_trip() {
+        let msg = sample_sync_message();
+        let mut buf = BytesMut::new();
+        msg.encode(None, &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let decoded = SyncMessage::from_buf(&mut frame, None).unwrap();
+        assert!(matches!(
+            decoded,
+            SyncMessage::V1(SyncMessageV1::Changeset { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn sync_message_sealed_round_trip() {
+        let key: ClusterKey = [7u8; 32];
+        let msg = sample_sync_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let decoded = SyncMessage::from_buf(&mut frame, Some(&key)).unwrap();
+        assert!(matches!(
+            decoded,
+            SyncMessage::V1(SyncMessageV1::Changeset { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn sync_message_sealed_frame_rejects_wrong_key() {
+        let key: ClusterKey = [7u8; 32];
+        let wrong_key: ClusterKey = [9u8; 32];
+        let msg = sample_sync_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let err = SyncMessage::from_buf(&mut frame, Some(&wrong_key)).unwrap_err();
+        assert!(matches!(err, SyncMessageDecodeError::Unauthenticated));
+    }
+
+    #[test]
+    fn sync_message_sealed_frame_without_key_is_rejected() {
+        let key: ClusterKey = [7u8; 32];
+        let msg = sample_sync_message();
+        let mut buf = BytesMut::new();
+        msg.encode(Some(&key), &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        let err = SyncMessage::from_buf(&mut frame, None).unwrap_err();
+        assert!(matches!(err, SyncMessageDecodeError::MissingKey));
+    }
+
+    #[test]
+    fn sync_message_plaintext_frame_rejects_corrupted_bytes() {
+        let msg = sample_sync_message();
+        let mut buf = BytesMut::new();
+        msg.encode(None, &mut buf).unwrap();
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .new_codec();
+        let mut frame = codec.decode(&mut buf).unwrap().unwrap();
+        // flip a byte in the plaintext payload, past the leading frame tag
+        frame[1] ^= 0xff;
+        let err = SyncMessage::from_buf(&mut frame, None).unwrap_err();
+        assert!(matches!(err, SyncMessageDecodeError::Corrupted(_, _)));
+    }
+
+    #[test]
+    fn sync_message_truncated_frame_is_rejected() {
+        let mut empty = BytesMut::new();
+        assert!(matches!(
+            SyncMessage::from_buf(&mut empty, None),
+            Err(SyncMessageDecodeError::Truncated)
+        ));
+
+        let mut short = BytesMut::from(&[FRAME_SEALED][..]);
+        assert!(matches!(
+            SyncMessage::from_buf(&mut short, Some(&[0u8; 32])),
+            Err(SyncMessageDecodeError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn needs_true_above_known_head() {
+        let mut state = SyncStateV1::default();
+        state.heads.insert(actor(), 5);
+        assert!(state.needs(actor(), 6));
+        assert!(!state.needs(actor(), 5));
+    }
+
+    #[test]
+    fn needs_true_within_a_tracked_gap() {
+        let mut state = SyncStateV1::default();
+        state.heads.insert(actor(), 10);
+        state.need.insert(actor(), vec![3..=4]);
+        assert!(state.needs(actor(), 3));
+        assert!(state.needs(actor(), 4));
+        assert!(!state.needs(actor(), 5));
+    }
+
+    #[test]
+    fn generate_sync_finds_gaps_between_booked_versions() {
+        let bookie = Bookie::default();
+        let actor_id = actor();
+        bookie.add(actor_id, 1, KnownDbVersion::Cleared);
+        bookie.add(actor_id, 3, KnownDbVersion::Cleared);
+
+        let state = generate_sync(&bookie, actor_id);
+        assert_eq!(state.heads.get(&actor_id), Some(&3));
+        assert_eq!(state.need.get(&actor_id), Some(&vec![2..=2]));
+    }
+
+    #[test]
+    fn changes_to_send_skips_cleared_versions() {
+        let bookie = Bookie::default();
+        let actor_id = actor();
+        bookie.add(actor_id, 1, KnownDbVersion::Cleared);
+
+        let their = SyncStateV1::default();
+        let needs = changes_to_send(&bookie, &their);
+        assert!(needs.is_empty());
+    }
+
+    #[test]
+    fn changes_to_send_offers_only_what_the_peer_is_missing() {
+        let bookie = Bookie::default();
+        let actor_id = actor();
+        bookie.add(
+            actor_id,
+            1,
+            KnownDbVersion::Current {
+                db_version: 1,
+                last_seq: 0,
+                ts: Default::default(),
+            },
+        );
+        bookie.add(
+            actor_id,
+            2,
+            KnownDbVersion::Current {
+                db_version: 2,
+                last_seq: 0,
+                ts: Default::default(),
+            },
+        );
+
+        let mut their = SyncStateV1::default();
+        their.heads.insert(actor_id, 1);
+
+        let needs = changes_to_send(&bookie, &their);
+        assert_eq!(
+            needs,
+            vec![SyncNeed {
+                actor_id,
+                version: 2,
+                seqs: None,
+            }]
+        );
+    }
+
+    fn store_with_threshold(max: usize) -> ChunkStore {
+        ChunkStore::new(
+            std::num::NonZeroUsize::new(64).unwrap(),
+            crate::config::ChunkConfig { min: 0, avg: 1, max },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn prepare_changeset_frame_inlines_changesets_under_the_threshold() {
+        let store = store_with_threshold(usize::MAX);
+        let msg = prepare_changeset_frame(&store, actor(), 1, Vec::new()).unwrap();
+        assert!(matches!(msg, SyncMessageV1::Changeset { version: 1, .. }));
+    }
+
+    #[test]
+    fn prepare_changeset_frame_chunks_changesets_over_the_threshold() {
+        let store = store_with_threshold(1);
+        let msg = prepare_changeset_frame(&store, actor(), 1, Vec::new()).unwrap();
+        assert!(matches!(msg, SyncMessageV1::ChunkManifest { version: 1, .. }));
+    }
+
+    #[test]
+    fn respond_to_chunk_message_requests_what_it_lacks() {
+        let store = store_with_threshold(usize::MAX);
+        let have = store.put(Bytes::from_static(b"x"));
+        let missing: ChunkId = [5; 32];
+
+        let manifest = SyncMessageV1::ChunkManifest {
+            actor_id: actor(),
+            version: 1,
+            ids: vec![have, missing],
+        };
+        match respond_to_chunk_message(&store, &manifest) {
+            Some(SyncMessageV1::ChunkRequest { ids }) => assert_eq!(ids, vec![missing]),
+            other => panic!("expected a ChunkRequest, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn respond_to_chunk_message_fulfills_a_request() {
+        let store = store_with_threshold(usize::MAX);
+        let have = store.put(Bytes::from_static(b"x"));
+
+        let request = SyncMessageV1::ChunkRequest { ids: vec![have] };
+        match respond_to_chunk_message(&store, &request) {
+            Some(SyncMessageV1::Chunks { chunks }) => {
+                assert_eq!(chunks.len(), 1);
+                assert_eq!(chunks[0].id, have);
+            }
+            other => panic!("expected Chunks, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn respond_to_chunk_message_ignores_non_chunk_variants() {
+        let store = store_with_threshold(usize::MAX);
+        let state = SyncMessageV1::State(SyncStateV1::default());
+        assert!(respond_to_chunk_message(&store, &state).is_none());
+    }
+
+    #[test]
+    fn chunked_changeset_round_trips_through_advertise_and_fulfill() {
+        let store = store_with_threshold(1);
+        let msg = prepare_changeset_frame(&store, actor(), 7, Vec::new()).unwrap();
+        let ids = match &msg {
+            SyncMessageV1::ChunkManifest { ids, .. } => ids.clone(),
+            other => panic!("expected a ChunkManifest, got {other:?}"),
+        };
+
+        let chunks = match fulfill_chunk_request(&store, &ids) {
+            SyncMessageV1::Chunks { chunks } => chunks,
+            other => panic!("expected Chunks, got {other:?}"),
+        };
+
+        let decoded = receive_chunks(&store, &ids, chunks).unwrap();
+        assert!(matches!(
+            decoded,
+            Message::V1(MessageV1::Change { version: 7, .. })
+        ));
+    }
+}